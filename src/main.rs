@@ -1,34 +1,99 @@
 use clap::Parser;
 use csv::{ReaderBuilder, Trim, Writer};
-use rust_decimal::Decimal;
+use rust_decimal::{Decimal, RoundingStrategy};
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DefaultOnError};
 
-use std::collections::{btree_map::Entry, BTreeMap};
+use std::collections::BTreeMap;
+use std::io::Read;
 use std::path::{Path, PathBuf};
 
 type ClientId = u16;
 type TransactionId = u32;
 
-// The main state of the program. Contains each client's current balance along with a record of
-// each Deposit transaction that was processed.
+/// The storage backend `handle_transaction` operates against. Abstracting over this lets
+/// `process_input` run against an in-memory store for small logs or a disk-/database-backed
+/// store for transaction logs too large to fit in RAM, without changing any processing logic.
+trait Store {
+    /// Returns the client's account, creating it with default balances if this is the first time
+    /// it's been referenced.
+    fn account(&mut self, client_id: ClientId) -> &mut Client;
+
+    /// Returns the transaction recorded under `key`, if any.
+    fn transaction(&mut self, key: (ClientId, TransactionId)) -> Option<&mut Transaction>;
+
+    /// Records a brand-new transaction under `key`. Callers are expected to have already checked
+    /// `transaction` returned `None` for this key.
+    fn insert_transaction(&mut self, key: (ClientId, TransactionId), transaction: Transaction);
+
+    /// Iterates every known account in client-id order for output.
+    fn accounts(&self) -> Box<dyn Iterator<Item = (ClientId, Client)> + '_>;
+}
+
+// The in-memory store backing the program today. Contains each client's current balance along
+// with a record of each Deposit and Withdrawal transaction that was processed.
+//
+// Transactions are keyed on `(ClientId, TransactionId)` rather than just `TransactionId` so that
+// a dispute/resolve/chargeback referencing a transaction ID belonging to another client simply
+// misses the lookup instead of mutating someone else's record.
 #[derive(Default)]
-struct State {
+struct MemStore {
     clients: BTreeMap<ClientId, Client>,
-    transactions: BTreeMap<TransactionId, Transaction>,
+    transactions: BTreeMap<(ClientId, TransactionId), Transaction>,
 }
 
-#[derive(Default)]
+impl Store for MemStore {
+    fn account(&mut self, client_id: ClientId) -> &mut Client {
+        self.clients.entry(client_id).or_default()
+    }
+
+    fn transaction(&mut self, key: (ClientId, TransactionId)) -> Option<&mut Transaction> {
+        self.transactions.get_mut(&key)
+    }
+
+    fn insert_transaction(&mut self, key: (ClientId, TransactionId), transaction: Transaction) {
+        self.transactions.insert(key, transaction);
+    }
+
+    fn accounts(&self) -> Box<dyn Iterator<Item = (ClientId, Client)> + '_> {
+        Box::new(self.clients.iter().map(|(&id, &client)| (id, client)))
+    }
+}
+
+#[derive(Default, Clone, Copy)]
 struct Client {
     available: Decimal,
     held: Decimal,
     locked: bool,
 }
 
-#[derive(Default)]
 struct Transaction {
     amount: Decimal,
-    disputed: bool,
+    kind: TxKind,
+    state: TxState,
+}
+
+// Whether a stored transaction was a Deposit or a Withdrawal. Disputing the two isn't
+// symmetric: a disputed deposit holds an inflow (funds the client already has, pending
+// removal), while a disputed withdrawal holds the *reversal* of an outflow (funds the client
+// no longer has, pending return) — see the sign rules in `handle_transaction`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum TxKind {
+    Deposit,
+    Withdrawal,
+}
+
+// Tracks where a transaction sits in its dispute lifecycle. A transaction starts out
+// `Processed` and can only move along `Processed -> Disputed -> {Resolved, ChargedBack}`.
+// Only a `Processed` transaction may be disputed, so `Resolved` and `ChargedBack` are
+// both terminal as far as re-disputing goes.
+#[derive(Default, PartialEq, Eq)]
+enum TxState {
+    #[default]
+    Processed,
+    Disputed,
+    Resolved,
+    ChargedBack,
 }
 
 #[derive(Deserialize)]
@@ -43,9 +108,45 @@ enum TransactionKind {
 
 #[derive(Parser)]
 struct Cli {
-    file_name: PathBuf,
+    /// Input CSV files to process, in order. Use `-` to read from stdin; sources (including a
+    /// repeated `-`) are drained in the order given as one logical transaction stream. Defaults
+    /// to stdin when none are given.
+    #[arg(default_value = "-")]
+    file_names: Vec<PathBuf>,
+
+    /// Print each rejected transaction's source and line number and reason to stderr instead of
+    /// silently dropping it. Stdout balance output is produced the same way either way.
+    #[arg(long)]
+    strict: bool,
+}
+
+/// The business-rule failures `handle_transaction` can reject a row for. These are never fatal:
+/// the row is simply skipped, but `--strict` surfaces the reason to stderr.
+#[derive(Debug)]
+enum LedgerError {
+    NotEnoughFunds,
+    UnknownTx { client: ClientId, tx: TransactionId },
+    AlreadyDisputed,
+    NotDisputed,
+    FrozenAccount,
+}
+
+impl std::fmt::Display for LedgerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LedgerError::NotEnoughFunds => write!(f, "withdrawal would overdraw the account"),
+            LedgerError::UnknownTx { client, tx } => {
+                write!(f, "no transaction {tx} on record for client {client}")
+            }
+            LedgerError::AlreadyDisputed => write!(f, "transaction cannot be disputed again"),
+            LedgerError::NotDisputed => write!(f, "transaction is not currently disputed"),
+            LedgerError::FrozenAccount => write!(f, "account is locked"),
+        }
+    }
 }
 
+impl std::error::Error for LedgerError {}
+
 #[serde_as]
 #[derive(Deserialize)]
 struct InputRow {
@@ -61,6 +162,7 @@ struct InputRow {
 }
 
 #[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
 struct OutputRow {
     client: ClientId,
     available: Decimal,
@@ -72,110 +174,210 @@ struct OutputRow {
 fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
 
-    let state = process_input(&cli.file_name)?;
+    let store: MemStore = process_input(&cli.file_names, cli.strict)?;
 
-    write_output(state)?;
+    write_output(&store)?;
 
     Ok(())
 }
 
-/// This function reads the input csv file and then builds up a list of clients and their balances
-/// from the incoming transaction stream
-fn process_input(file_name: &Path) -> anyhow::Result<State> {
-    let mut reader = ReaderBuilder::new()
-        .has_headers(true)
-        .flexible(true)
-        .trim(Trim::All)
-        .from_path(file_name)?;
+/// Opens `file_name` for reading, or stdin if it's the `-` sentinel.
+fn open_source(file_name: &Path) -> anyhow::Result<Box<dyn Read>> {
+    if file_name.as_os_str() == "-" {
+        Ok(Box::new(std::io::stdin()))
+    } else {
+        Ok(Box::new(std::fs::File::open(file_name)?))
+    }
+}
 
-    let mut state = State::default();
+/// This function reads the input csv files (or stdin) in order and builds up a list of clients
+/// and their balances from the incoming transaction stream. Sources are drained one after
+/// another into the same store, so memory stays bounded to the account/transaction state rather
+/// than the size of the inputs. Rejected rows are always skipped; when `strict` is set, each one
+/// is also reported to stderr with its source and line number.
+fn process_input<S: Store + Default>(file_names: &[PathBuf], strict: bool) -> anyhow::Result<S> {
+    let mut store = S::default();
+
+    for file_name in file_names {
+        let mut reader = ReaderBuilder::new()
+            .has_headers(true)
+            .flexible(true)
+            .trim(Trim::All)
+            .from_reader(open_source(file_name)?);
+
+        let headers = reader.headers()?.clone();
+        let mut record = csv::StringRecord::new();
+
+        while reader.read_record(&mut record)? {
+            let line = record.position().map_or(0, |pos| pos.line());
+            let input: InputRow = record.deserialize(Some(&headers))?;
+
+            if let Err(err) = handle_transaction(&mut store, input) {
+                if strict {
+                    eprintln!("{}: line {line}: {err}", file_name.display());
+                }
+            }
+        }
+    }
 
-    for row in reader.deserialize() {
-        let input: InputRow = row?;
+    Ok(store)
+}
 
-        handle_transaction(&mut state, input)?;
+/// Records or updates the stored transaction for `key` so it can later be disputed.
+fn record_transaction<S: Store>(
+    store: &mut S,
+    key: (ClientId, TransactionId),
+    amount: Decimal,
+    kind: TxKind,
+) {
+    match store.transaction(key) {
+        Some(transaction) => {
+            transaction.amount = amount;
+            transaction.kind = kind;
+        }
+        None => store.insert_transaction(
+            key,
+            Transaction {
+                amount,
+                kind,
+                state: TxState::Processed,
+            },
+        ),
     }
-
-    Ok(state)
 }
 
-/// Handles the five different kinds of transactions and updates the State accordingly
-fn handle_transaction(state: &mut State, input: InputRow) -> anyhow::Result<()> {
-    // Create or get a client as soon as one is referenced in the transaction stream
-    let client = state.clients.entry(input.client_id).or_default();
+/// Handles the five different kinds of transactions and updates the store accordingly, rejecting
+/// the row with a `LedgerError` if it violates a business rule.
+fn handle_transaction<S: Store>(store: &mut S, input: InputRow) -> Result<(), LedgerError> {
+    let key = (input.client_id, input.txn_id);
 
-    // Defer creation of a transaction record until a Deposit occurs
-    let txn_entry = state.transactions.entry(input.txn_id);
+    if store.account(input.client_id).locked {
+        return Err(LedgerError::FrozenAccount);
+    }
 
     match input.kind {
         TransactionKind::Deposit => {
-            if !client.locked {
-                client.available += input.amount;
-
-                txn_entry.or_default().amount = input.amount;
-            }
+            store.account(input.client_id).available += input.amount;
+            record_transaction(store, key, input.amount, TxKind::Deposit);
         }
         TransactionKind::Withdrawal => {
-            if !client.locked {
-                let diff = client.available - input.amount;
-
-                if diff.is_sign_negative() {
-                    return Ok(());
-                }
+            let client = store.account(input.client_id);
+            let diff = client.available - input.amount;
 
-                client.available = diff;
+            if diff.is_sign_negative() {
+                return Err(LedgerError::NotEnoughFunds);
             }
+
+            client.available = diff;
+            record_transaction(store, key, input.amount, TxKind::Withdrawal);
         }
         TransactionKind::Dispute => {
-            if let Entry::Occupied(mut entry) = txn_entry {
-                let transaction = entry.get_mut();
+            let (amount, kind) = {
+                let transaction = store.transaction(key).ok_or(LedgerError::UnknownTx {
+                    client: input.client_id,
+                    tx: input.txn_id,
+                })?;
+
+                if transaction.state != TxState::Processed {
+                    return Err(LedgerError::AlreadyDisputed);
+                }
 
-                if !client.locked && !transaction.disputed {
-                    client.held += transaction.amount;
-                    client.available -= transaction.amount;
+                transaction.state = TxState::Disputed;
+                (transaction.amount, transaction.kind)
+            };
 
-                    transaction.disputed = true;
-                }
+            // A disputed deposit holds an inflow: pull it out of `available` and into `held`.
+            // A disputed withdrawal holds the reversal of an outflow: the funds already left
+            // `available` when the withdrawal was processed, so only `held` grows here.
+            let client = store.account(input.client_id);
+            client.held += amount;
+
+            if kind == TxKind::Deposit {
+                client.available -= amount;
             }
         }
         TransactionKind::Resolve => {
-            if let Entry::Occupied(mut entry) = txn_entry {
-                let transaction = entry.get_mut();
+            let (amount, kind) = {
+                let transaction = store.transaction(key).ok_or(LedgerError::UnknownTx {
+                    client: input.client_id,
+                    tx: input.txn_id,
+                })?;
+
+                if transaction.state != TxState::Disputed {
+                    return Err(LedgerError::NotDisputed);
+                }
 
-                if !client.locked && transaction.disputed {
-                    client.held -= transaction.amount;
-                    client.available += transaction.amount;
+                transaction.state = TxState::Resolved;
+                (transaction.amount, transaction.kind)
+            };
 
-                    transaction.disputed = false;
-                }
+            // A resolved deposit dispute releases the hold back to `available`. A resolved
+            // withdrawal dispute just drops the hold: the withdrawal stands, so `available`
+            // was never touched in the first place.
+            let client = store.account(input.client_id);
+            client.held -= amount;
+
+            if kind == TxKind::Deposit {
+                client.available += amount;
             }
         }
         TransactionKind::Chargeback => {
-            if let Entry::Occupied(mut entry) = txn_entry {
-                let transaction = entry.get_mut();
+            let (amount, kind) = {
+                let transaction = store.transaction(key).ok_or(LedgerError::UnknownTx {
+                    client: input.client_id,
+                    tx: input.txn_id,
+                })?;
+
+                if transaction.state != TxState::Disputed {
+                    return Err(LedgerError::NotDisputed);
+                }
 
-                if !client.locked && transaction.disputed {
-                    client.held -= transaction.amount;
+                transaction.state = TxState::ChargedBack;
+                (transaction.amount, transaction.kind)
+            };
 
-                    client.locked = true;
-                }
+            // A charged-back deposit is undone: the hold is simply dropped, shrinking `total`.
+            // A charged-back withdrawal is undone the other way: the funds are given back to
+            // `available`, restoring `total` to what it was before the withdrawal.
+            let client = store.account(input.client_id);
+            client.held -= amount;
+
+            if kind == TxKind::Withdrawal {
+                client.available += amount;
             }
+
+            client.locked = true;
         }
     }
 
     Ok(())
 }
 
-/// Writes program output to stdout
-fn write_output(state: State) -> anyhow::Result<()> {
-    let mut writer = Writer::from_writer(std::io::stdout());
+/// Writes program output to stdout.
+fn write_output<S: Store>(store: &S) -> anyhow::Result<()> {
+    write_rows(store, std::io::stdout())
+}
+
+/// Writes program output to `writer`. `available` and `held` are each rounded to 4 decimal
+/// places with banker's rounding before `total` is derived from the rounded pair, so the
+/// invariant `total == available + held` always holds on the printed row, not just on the raw
+/// state.
+fn write_rows<S: Store, W: std::io::Write>(store: &S, writer: W) -> anyhow::Result<()> {
+    let mut writer = Writer::from_writer(writer);
+
+    for (client_id, client) in store.accounts() {
+        let available = client
+            .available
+            .round_dp_with_strategy(4, RoundingStrategy::MidpointNearestEven);
+        let held = client
+            .held
+            .round_dp_with_strategy(4, RoundingStrategy::MidpointNearestEven);
 
-    for (client_id, client) in state.clients {
         let output = OutputRow {
             client: client_id,
-            available: client.available,
-            held: client.held,
-            total: client.available + client.held,
+            available,
+            held,
+            total: available + held,
             locked: client.locked,
         };
 
@@ -184,3 +386,84 @@ fn write_output(state: State) -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(
+        kind: TransactionKind,
+        client_id: ClientId,
+        txn_id: TransactionId,
+        amount: Decimal,
+    ) -> InputRow {
+        InputRow {
+            kind,
+            client_id,
+            txn_id,
+            amount,
+        }
+    }
+
+    fn output_rows<S: Store>(store: &S) -> Vec<OutputRow> {
+        let mut buf = Vec::new();
+        write_rows(store, &mut buf).unwrap();
+
+        ReaderBuilder::new()
+            .has_headers(true)
+            .from_reader(buf.as_slice())
+            .deserialize()
+            .map(|row| row.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn withdrawal_chargeback_keeps_total_balanced() {
+        let mut store = MemStore::default();
+
+        handle_transaction(
+            &mut store,
+            input(TransactionKind::Deposit, 1, 1, Decimal::new(1000, 2)),
+        )
+        .unwrap();
+        handle_transaction(
+            &mut store,
+            input(TransactionKind::Withdrawal, 1, 2, Decimal::new(400, 2)),
+        )
+        .unwrap();
+        handle_transaction(
+            &mut store,
+            input(TransactionKind::Dispute, 1, 2, Decimal::ZERO),
+        )
+        .unwrap();
+        handle_transaction(
+            &mut store,
+            input(TransactionKind::Chargeback, 1, 2, Decimal::ZERO),
+        )
+        .unwrap();
+
+        let rows = output_rows(&store);
+        let row = &rows[0];
+
+        // The disputed withdrawal was charged back, so the funds are returned in full.
+        assert_eq!(row.available, Decimal::new(1000, 2));
+        assert_eq!(row.held, Decimal::ZERO);
+        assert!(row.locked);
+        assert_eq!(row.total, row.available + row.held);
+    }
+
+    #[test]
+    fn rounds_to_four_decimal_places_with_bankers_rounding() {
+        let mut store = MemStore::default();
+        let client = store.account(1);
+        client.available = Decimal::new(274235, 5); // 2.74235, midpoint -> even 2.7424
+        client.held = Decimal::new(100005, 5); // 1.00005, midpoint -> even 1.0000
+
+        let rows = output_rows(&store);
+        let row = &rows[0];
+
+        assert_eq!(row.available, Decimal::new(27424, 4));
+        assert_eq!(row.held, Decimal::new(10000, 4));
+        assert_eq!(row.total, row.available + row.held);
+    }
+}